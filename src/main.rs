@@ -8,9 +8,15 @@ use opentelemetry_sdk::{
     metrics::{MeterProvider, PeriodicReader},
     runtime,
 };
+use futures::stream::{self, StreamExt};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use prometheus::{Encoder, Registry, TextEncoder};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use tokio_util::sync::CancellationToken;
 use url::Url;
@@ -32,6 +38,53 @@ pub struct Cli {
     /// Whether to print out the metrics we are publishing to stdout
     #[clap(long)]
     pub debug: bool,
+
+    /// Expose a Prometheus `/metrics` endpoint on this address instead of
+    /// relying solely on the OTLP push exporter. Handy when operators already
+    /// run Prometheus and would rather scrape this process than stand up a
+    /// collector.
+    #[clap(long)]
+    pub prometheus_listen: Option<SocketAddr>,
+
+    /// Maximum number of in-flight per-job scale requests. On clusters with
+    /// hundreds of jobs this keeps a single poll from exceeding the interval.
+    #[clap(long, default_value = "16")]
+    pub max_concurrent_requests: usize,
+
+    /// Per-request timeout applied to every call to nomad.
+    #[clap(long, default_value = "10s")]
+    pub request_timeout: Duration,
+
+    /// ACL token presented to nomad via the `X-Nomad-Token` header. Also read
+    /// from the `NOMAD_TOKEN` environment variable.
+    #[clap(long, env = "NOMAD_TOKEN")]
+    pub nomad_token: Option<String>,
+
+    /// Nomad namespace to query. Defaults to `*`, covering every namespace.
+    #[clap(long, default_value = "*")]
+    pub nomad_namespace: String,
+
+    /// Which job instruments to register and emit. Anything left out is never
+    /// instantiated, so it stays out of the OTLP/stdout/Prometheus output
+    /// entirely — handy for keeping cardinality down.
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "up,down,ratio,desired,placed,running"
+    )]
+    pub metrics: Vec<JobMetric>,
+}
+
+/// A job instrument that can be individually selected via `--metrics`.
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum JobMetric {
+    Up,
+    Down,
+    Ratio,
+    Desired,
+    Placed,
+    Running,
 }
 
 #[tokio::main]
@@ -40,50 +93,127 @@ async fn main() -> Result<()> {
     let args = Cli::parse();
     info!("Polling {} every {}", args.nomad_url, args.nomad_poll_interval);
 
-    let meter_provider = setup_otel(args.debug)?;
-    let closable_meter_provider = meter_provider.clone();
+    let (meter_provider, prometheus_registry) = setup_otel(args.debug, args.prometheus_listen)?;
     let meter = meter_provider.meter("nomad_metrics");
 
-    let status_ratio = meter
-        .f64_observable_gauge("nomad_job_status_ratio")
-        .with_description("The ratio of working relative to expected count for each nomad job")
-        .init();
+    if let (Some(addr), Some(registry)) = (args.prometheus_listen, prometheus_registry) {
+        info!("Serving Prometheus metrics on http://{}/metrics", addr);
+        tokio::spawn(serve_prometheus(addr, registry));
+    }
+
+    let selected: std::collections::HashSet<JobMetric> = args.metrics.iter().copied().collect();
 
-    let service_up = meter.u64_observable_gauge("nomad_job_up").init();
-    let service_down = meter.u64_observable_gauge("nomad_job_down").init();
+    // Only instantiate the instruments the operator asked for; an un-init'ed
+    // instrument never surfaces through any reader.
+    let status_ratio = selected.contains(&JobMetric::Ratio).then(|| {
+        meter
+            .f64_observable_gauge("nomad_job_status_ratio")
+            .with_description("The ratio of working relative to expected count for each nomad job")
+            .init()
+    });
+
+    let service_up = selected
+        .contains(&JobMetric::Up)
+        .then(|| meter.u64_observable_gauge("nomad_job_up").init());
+    let service_down = selected
+        .contains(&JobMetric::Down)
+        .then(|| meter.u64_observable_gauge("nomad_job_down").init());
+    let service_desired = selected
+        .contains(&JobMetric::Desired)
+        .then(|| meter.u64_observable_gauge("nomad_job_desired").init());
+    let service_placed = selected
+        .contains(&JobMetric::Placed)
+        .then(|| meter.u64_observable_gauge("nomad_job_placed").init());
+    let service_running = selected
+        .contains(&JobMetric::Running)
+        .then(|| meter.u64_observable_gauge("nomad_job_running").init());
+
+    let scrape_errors = meter
+        .u64_observable_counter("nomad_scrape_errors_total")
+        .with_description("Total number of failed attempts to scrape job statuses from nomad")
+        .init();
+    let scrape_success = meter
+        .u64_observable_gauge("nomad_scrape_success")
+        .with_description("Whether the most recent scrape of nomad succeeded (1) or failed (0)")
+        .init();
 
     let nomad_url = args.nomad_url.to_string();
 
-    let job_metric_map = Arc::new(Mutex::new(HashMap::<String, StatusCount>::new()));
+    // Build the client once, with timeouts, and reuse it across polls rather
+    // than constructing a fresh `Client` on every call.
+    let client = Client::builder()
+        .timeout(args.request_timeout.into())
+        .build()?;
+    let max_concurrent_requests = args.max_concurrent_requests;
+    let nomad_token = args.nomad_token.clone();
+    let nomad_namespace = args.nomad_namespace.clone();
+
+    let job_metric_map = Arc::new(Mutex::new(HashMap::<JobKey, StatusCount>::new()));
     let looper_job_metric_map = job_metric_map.clone();
 
+    let scrape_health = Arc::new(Mutex::new(ScrapeHealth::default()));
+    let looper_scrape_health = scrape_health.clone();
+
     let cancel_token = CancellationToken::new();
     let status_checker_token = cancel_token.clone();
 
+    let poll_interval: std::time::Duration = args.nomad_poll_interval.into();
+
     let status_loop = tokio::spawn(async move {
+        // Normally we sleep a full poll interval between scrapes. After a
+        // failure we retry sooner, growing the delay exponentially up to (but
+        // never beyond) the poll interval so a briefly unreachable nomad is
+        // picked back up promptly without being hammered.
+        let base_backoff = std::time::Duration::from_secs(1);
+        let mut next_delay = poll_interval;
+        let mut backoff = base_backoff;
         loop {
             tokio::select! {
                 _ = status_checker_token.cancelled() => {
                     return
                 }
-                _ = tokio::time::sleep(args.nomad_poll_interval.into()) => {
-                // TODO: Set timeouts
-                let statuses = get_statuses_for_jobs(nomad_url.clone())
-                    .await
-                    .expect("Unable to fetch statuses from the provided domain");
-
-                {
-                    // in it's own scope so we don't keep the lock for too long.
-                    let mut data = looper_job_metric_map.lock().unwrap();
-                    for (job_name, status) in statuses.iter() {
-                        let status_count = StatusCount {
-                            up: status.healthy.into(),
-                            down: status.unhealthy.into(),
-                            up_ratio: (status.healthy / status.desired).into(),
-                        };
-
-                        debug!("Job {} had status {:?}", job_name, status_count);
-                        data.insert(job_name.clone(), status_count);
+                _ = tokio::time::sleep(next_delay) => {
+                match get_statuses_for_jobs(
+                    &client,
+                    nomad_url.clone(),
+                    max_concurrent_requests,
+                    nomad_token.as_deref(),
+                    &nomad_namespace,
+                ).await {
+                    Ok(statuses) => {
+                        {
+                            // in it's own scope so we don't keep the lock for too long.
+                            let mut data = looper_job_metric_map.lock().unwrap();
+                            for (job_key, status) in statuses.iter() {
+                                let status_count = StatusCount {
+                                    up: status.healthy.into(),
+                                    down: status.unhealthy.into(),
+                                    up_ratio: if status.desired == 0 {
+                                        0.0
+                                    } else {
+                                        f64::from(status.healthy) / f64::from(status.desired)
+                                    },
+                                    desired: status.desired.into(),
+                                    placed: status.placed.into(),
+                                    running: status.running.into(),
+                                };
+
+                                debug!("Job {:?} had status {:?}", job_key, status_count);
+                                data.insert(job_key.clone(), status_count);
+                            }
+                        }
+
+                        looper_scrape_health.lock().unwrap().record_success();
+                        next_delay = poll_interval;
+                        backoff = base_backoff;
+                    }
+                    Err(err) => {
+                        // Leave the previous job_metric_map values intact so
+                        // stale gauges persist, and try again sooner.
+                        error!("Failed to scrape statuses from nomad: {:#}", err);
+                        looper_scrape_health.lock().unwrap().record_failure();
+                        next_delay = backoff.min(poll_interval);
+                        backoff = (backoff * 2).min(poll_interval);
                     }
                 }
 
@@ -92,64 +222,115 @@ async fn main() -> Result<()> {
         }
     });
 
-    tokio::spawn(async move {
-        // wait for ctrlc
-        match tokio::signal::ctrl_c().await {
-            Ok(()) => {
-                // TODO: This should really be blow the metric provider closing,
-                // b/c it just hard-stops the binary. Unfortunately, the
-                // shutdown mechanism below hangs. Pending
-                // https://cloud-native.slack.com/archives/C03GDP0H023/p1706210769680649
-                cancel_token.cancel();
-
-                trace!("Provider, as we know it. {:#?}", closable_meter_provider);
-                info!("Flushing metrics.");
-                closable_meter_provider.force_flush()?;
-                info!("Shutting it down.");
-                closable_meter_provider.shutdown()?;
-                info!("Meter provider is shutdown");
-                Ok::<(), anyhow::Error>(())
-            }
-            Err(err) => {
-                error!("Unable to listen for shutdown signal. Ending. {}", err);
-                cancel_token.cancel();
-                Ok(())
-            }
+    let mut instruments = vec![scrape_errors.as_any(), scrape_success.as_any()];
+    for gauge in [&service_up, &service_down, &service_desired, &service_placed, &service_running] {
+        if let Some(gauge) = gauge {
+            instruments.push(gauge.as_any());
         }
-    });
+    }
+    if let Some(status_ratio) = &status_ratio {
+        instruments.push(status_ratio.as_any());
+    }
 
-    meter.register_callback(
-        &[
-            service_up.as_any(),
-            service_down.as_any(),
-            status_ratio.as_any(),
-        ],
-        move |observer| {
-            let data = job_metric_map.lock().unwrap();
-            for (job_name, status_count) in data.iter() {
-                let labels = [KeyValue::new(
+    meter.register_callback(&instruments, move |observer| {
+        let data = job_metric_map.lock().unwrap();
+        for (job_key, status_count) in data.iter() {
+            let labels = [
+                KeyValue::new(
                     // NB: "job" is a reserved word for these.
                     "nomad_job",
-                    Value::String(StringValue::from(job_name.to_owned())),
-                )];
+                    Value::String(StringValue::from(job_key.name.to_owned())),
+                ),
+                KeyValue::new(
+                    "nomad_namespace",
+                    Value::String(StringValue::from(job_key.namespace.to_owned())),
+                ),
+            ];
 
-                observer.observe_u64(&service_up, status_count.up, &labels);
-                observer.observe_u64(&service_down, status_count.down, &labels);
-                observer.observe_f64(&status_ratio, status_count.up_ratio, &labels);
+            if let Some(service_up) = &service_up {
+                observer.observe_u64(service_up, status_count.up, &labels);
             }
-        },
-    )?;
+            if let Some(service_down) = &service_down {
+                observer.observe_u64(service_down, status_count.down, &labels);
+            }
+            if let Some(status_ratio) = &status_ratio {
+                observer.observe_f64(status_ratio, status_count.up_ratio, &labels);
+            }
+            if let Some(service_desired) = &service_desired {
+                observer.observe_u64(service_desired, status_count.desired, &labels);
+            }
+            if let Some(service_placed) = &service_placed {
+                observer.observe_u64(service_placed, status_count.placed, &labels);
+            }
+            if let Some(service_running) = &service_running {
+                observer.observe_u64(service_running, status_count.running, &labels);
+            }
+        }
+
+        let health = scrape_health.lock().unwrap();
+        observer.observe_u64(&scrape_errors, health.errors, &[]);
+        observer.observe_u64(&scrape_success, health.success, &[]);
+    })?;
 
+    // Wait for a shutdown signal. When running as a Nomad/Kubernetes task the
+    // process is stopped with SIGTERM, not SIGINT, so we have to listen for
+    // both or we get hard-killed before the last metrics window is flushed.
+    wait_for_shutdown().await;
+    info!("Shutdown signal received, stopping poll loop.");
+    cancel_token.cancel();
+
+    // Let the poll loop observe the cancellation and return before we tear the
+    // provider down, then flush + shut down inline so the final window is
+    // actually exported rather than racing process exit.
     status_loop.await?;
 
+    trace!("Provider, as we know it. {:#?}", meter_provider);
+    info!("Flushing metrics.");
+    meter_provider.force_flush()?;
+    info!("Shutting it down.");
+    meter_provider.shutdown()?;
+    info!("Meter provider is shutdown");
+
     Ok(())
 }
 
-fn setup_otel(debug: bool) -> Result<Arc<MeterProvider>> {
+/// Resolve once either SIGTERM or SIGINT is received.
+async fn wait_for_shutdown() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm =
+        signal(SignalKind::terminate()).expect("unable to register SIGTERM handler");
+    let mut sigint =
+        signal(SignalKind::interrupt()).expect("unable to register SIGINT handler");
+
+    tokio::select! {
+        _ = sigterm.recv() => debug!("Received SIGTERM."),
+        _ = sigint.recv() => debug!("Received SIGINT."),
+    }
+}
+
+fn setup_otel(
+    debug: bool,
+    prometheus_listen: Option<SocketAddr>,
+) -> Result<(Arc<MeterProvider>, Option<Registry>)> {
 
     let mut builder =         MeterProvider::builder()
         .with_resource(Resource::new(vec![KeyValue::new("service.name", "nomad-scraper")]));
 
+    // When a Prometheus listen address is configured we add a pull exporter
+    // alongside the OTLP reader. The same observable gauges flow through
+    // whichever reader is active, so the registered callback stays unchanged.
+    let registry = if prometheus_listen.is_some() {
+        let registry = Registry::new();
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_registry(registry.clone())
+            .build()?;
+        builder = builder.with_reader(exporter);
+        Some(registry)
+    } else {
+        None
+    };
+
     if debug {
         builder = builder.with_reader(PeriodicReader::builder(
             opentelemetry_stdout::MetricsExporterBuilder::default()
@@ -174,39 +355,129 @@ fn setup_otel(debug: bool) -> Result<Arc<MeterProvider>> {
     )
                                       .build());
 
-    Ok(Arc::new(builder.build()))
+    Ok((Arc::new(builder.build()), registry))
+}
+
+/// Serve the Prometheus registry's text encoding at `/metrics`.
+async fn serve_prometheus(addr: SocketAddr, registry: Registry) {
+    let make_service = make_service_fn(move |_conn| {
+        let registry = registry.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let registry = registry.clone();
+                async move { Ok::<_, Infallible>(metrics_response(req, &registry)) }
+            }))
+        }
+    });
+
+    if let Err(err) = Server::bind(&addr).serve(make_service).await {
+        error!("Prometheus metrics server error: {}", err);
+    }
+}
+
+fn metrics_response(req: Request<Body>, registry: &Registry) -> Response<Body> {
+    if req.uri().path() != "/metrics" {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("not found"))
+            .unwrap();
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(err) = encoder.encode(&registry.gather(), &mut buffer) {
+        error!("Unable to encode Prometheus metrics: {}", err);
+        return Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap()
 }
 
-async fn get_statuses_for_jobs(nomad_url: String) -> Result<Vec<(String, JobScaleStatus)>> {
-    let client = Client::new();
-    let entries = client
-        .get(format!("{}v1/jobs", nomad_url))
-        .send()
-        .await?
-        .json::<Vec<JobListEntry>>()
-        .await?;
+async fn get_statuses_for_jobs(
+    client: &Client,
+    nomad_url: String,
+    max_concurrent_requests: usize,
+    nomad_token: Option<&str>,
+    nomad_namespace: &str,
+) -> Result<Vec<(JobKey, JobScaleStatus)>> {
+    let entries = authed(
+        client.get(format!("{}v1/jobs?namespace={}", nomad_url, nomad_namespace)),
+        nomad_token,
+    )
+    .send()
+    .await?
+    .json::<Vec<JobListEntry>>()
+    .await?;
+
+    // Issue the per-job scale lookups concurrently, capping the number of
+    // in-flight requests so we don't hammer nomad on large clusters.
+    let scales = stream::iter(entries)
+        .map(|entry| {
+            let client = client.clone();
+            let nomad_url = nomad_url.clone();
+            async move {
+                trace!("Looking up status for {} in {}..", entry.name, entry.namespace);
+                let job_scale = authed(
+                    client.get(format!(
+                        "{}v1/job/{}/scale?namespace={}",
+                        nomad_url, entry.name, entry.namespace
+                    )),
+                    nomad_token,
+                )
+                .send()
+                .await?
+                .json::<JobScale>()
+                .await?;
+                Ok::<_, anyhow::Error>((entry.namespace, job_scale))
+            }
+        })
+        .buffer_unordered(max_concurrent_requests)
+        .collect::<Vec<_>>()
+        .await;
+
     let mut statuses = Vec::new();
-    for entry in entries.iter() {
-        let job_name = &entry.name;
-        trace!("Looking up status for {}..", job_name);
-        let job_scale = client
-            .get(format!("{}v1/job/{}/scale", nomad_url, job_name))
-            .send()
-            .await?
-            .json::<JobScale>()
-            .await?;
+    for scale in scales {
+        let (namespace, job_scale) = scale?;
         for (name, job_status) in job_scale.task_groups.iter() {
-            // TODO: This should likely yield, but I'm not entirely sure how to accomplish that w/ Result.
-            statuses.push((name.to_owned(), job_status.to_owned()));
+            let key = JobKey {
+                namespace: namespace.clone(),
+                name: name.to_owned(),
+            };
+            statuses.push((key, job_status.to_owned()));
         }
     }
     Ok(statuses)
 }
 
+/// Attach the nomad ACL token header to a request when one is configured.
+fn authed(builder: reqwest::RequestBuilder, nomad_token: Option<&str>) -> reqwest::RequestBuilder {
+    match nomad_token {
+        Some(token) => builder.header("X-Nomad-Token", token),
+        None => builder,
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct JobListEntry {
     #[serde(rename = "Name")]
     name: String,
+    #[serde(rename = "Namespace")]
+    namespace: String,
+}
+
+/// Identifies a task group within a namespace. Keyed on both so jobs sharing
+/// a name across namespaces don't collide in the `job_metric_map`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct JobKey {
+    namespace: String,
+    name: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -229,9 +500,33 @@ struct JobScaleStatus {
     unhealthy: u32,
 }
 
+/// Tracks the scraper's own contact with nomad so consumers can alert on it
+/// losing sight of the cluster even while stale job gauges persist.
+#[derive(Debug, Default)]
+struct ScrapeHealth {
+    /// Cumulative count of failed scrapes, surfaced as a counter.
+    errors: u64,
+    /// Whether the most recent scrape succeeded (1) or failed (0).
+    success: u64,
+}
+
+impl ScrapeHealth {
+    fn record_success(&mut self) {
+        self.success = 1;
+    }
+
+    fn record_failure(&mut self) {
+        self.errors += 1;
+        self.success = 0;
+    }
+}
+
 #[derive(Debug)]
 struct StatusCount {
     up: u64,
     down: u64,
     up_ratio: f64,
+    desired: u64,
+    placed: u64,
+    running: u64,
 }